@@ -1,4 +1,4 @@
-use broken_field::{bf, bytebeat, fractal};
+use broken_field::{bf, bytebeat, compiled, fractal};
 
 use num_complex::Complex;
 use pixel_canvas::{Color, Image};
@@ -86,6 +86,9 @@ impl Art for BrainfuckArt {
 
 pub struct BytebeatArt {
     pub program: bytebeat::Program,
+    // Lowered once here instead of re-matching every `Cmd` per pixel per
+    // frame in `update`'s hot loop; rebuilt whenever `program` changes.
+    compiled: compiled::CompiledProgram,
     pub image_data: Box<[u8]>,
     pub frame: i64,
 }
@@ -94,8 +97,10 @@ impl BytebeatArt {
     pub fn new_from(program: bytebeat::Program) -> BytebeatArt {
         println!("{}", program);
 
+        let compiled = program.compile_exec(bytebeat::DivideByZeroPolicy::SilentZero);
         BytebeatArt {
             program,
+            compiled,
             image_data: vec![0; BYTEBEAT_WIDTH * BYTEBEAT_HEIGHT].into_boxed_slice(),
             frame: 0,
         }
@@ -120,7 +125,7 @@ impl Art for BytebeatArt {
 
     fn update(&mut self, speed: i64, inputs: Inputs) {
         let t = self.frame;
-        let program = &self.program;
+        let compiled = &self.compiled;
         // Iterate over the image data, rendering the bytebeat to the internal image data
         self.image_data
             .par_chunks_mut(BYTEBEAT_WIDTH)
@@ -129,17 +134,23 @@ impl Art for BytebeatArt {
                 || Vec::with_capacity(32),
                 |stack, (screen_y, row)| {
                     for screen_x in 0..BYTEBEAT_HEIGHT {
-                        row[screen_x] = bytebeat::eval_beat(
+                        // A trap can only come from a hand-written program;
+                        // fall back to black rather than taking down the
+                        // render thread over one bad pixel.
+                        row[screen_x] = compiled::eval_compiled(
+                            compiled,
                             stack,
-                            program,
-                            t,
-                            inputs.mouse_x,
-                            inputs.mouse_y,
-                            screen_x as i64,
-                            screen_y as i64,
-                            inputs.key_x,
-                            inputs.key_y,
+                            &compiled::Inputs {
+                                t: bytebeat::Val::I(t),
+                                mouse_x: bytebeat::Val::I(inputs.mouse_x),
+                                mouse_y: bytebeat::Val::I(inputs.mouse_y),
+                                screen_x: bytebeat::Val::I(screen_x as i64),
+                                screen_y: bytebeat::Val::I(screen_y as i64),
+                                key_x: bytebeat::Val::I(inputs.key_x),
+                                key_y: bytebeat::Val::I(inputs.key_y),
+                            },
                         )
+                        .unwrap_or(bytebeat::Val::I(0))
                         .into();
                     }
                 },
@@ -3,6 +3,7 @@ use rand::seq::SliceRandom;
 use rand::thread_rng;
 use rand::Rng;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 // Implement the Distribution trait for the given enum. We randomly select from
 // all of the available variants. This only works if none of the variants have
@@ -53,12 +54,16 @@ pub enum LiteralType {
     NumF(f64),
     NumI(i64),
     Hex(i64),
+    /// A Unicode scalar value, pushed as its codepoint. Lets a program embed
+    /// a specific character (e.g. for `eval_text` output) instead of only
+    /// ever computing one via `Chr`.
+    Char(char),
 }
 
 impl Distribution<LiteralType> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> LiteralType {
         use LiteralType::*;
-        *[NumF(rng.gen()), NumI(rng.gen()), Hex(rng.gen())]
+        *[NumF(rng.gen()), NumI(rng.gen()), Hex(rng.gen()), Char(rng.gen())]
             .choose(rng)
             .unwrap()
     }
@@ -74,6 +79,19 @@ pub enum Cmd {
     Comp(CompType),
     Cond,
     Arr(usize),
+    /// Normalizes the top-of-stack value into a valid Unicode scalar value
+    /// (codepoint), the way `eval_text` needs its output to be displayable:
+    /// values outside the valid range, or landing on a surrogate, are
+    /// replaced with the replacement character's codepoint instead of
+    /// producing garbage.
+    Chr,
+    /// Opens a block that repeats its body (everything up to the matching
+    /// `End`) a fixed number of times. `compile` requires blocks to be
+    /// balanced and the body to have a net `stack_change` of zero, so the
+    /// loop is stack-safe no matter how many times it runs.
+    Loop(usize),
+    /// Closes the block opened by the most recently unmatched `Loop`.
+    End,
     Meta(String, String),
     Comment(String),
 }
@@ -100,9 +118,14 @@ impl Cmd {
             Var(_) | Literal(_) => 1,
             // These have no runtime effect
             Meta(_, _) | Comment(_) => 0,
+            // A balanced Loop/End block always leaves the stack the way it
+            // found it (compile() enforces this for the body), so the block
+            // markers themselves are a no-op on the stack.
+            Loop(_) | End => 0,
             // These all pop 1 value off the stack and push 1
             // value back on, so the net effect is no stack change
             Trig(_) => 0,
+            Chr => 0,
             // Arr(x) pops a value off the stack (called the index)
             // then pops x more values off the stack. Finally, it
             // pushes one value back onto the stack based on the index
@@ -124,6 +147,10 @@ impl Cmd {
 pub struct Program {
     cmds: Vec<Cmd>,
     meta: HashMap<String, Vec<String>>,
+    /// Maps the index of each `Loop` to the index of its matching `End`,
+    /// precomputed by `compile` so `eval_beat` doesn't have to re-scan for
+    /// it on every loop iteration.
+    loop_ends: HashMap<usize, usize>,
 }
 
 impl Program {
@@ -134,6 +161,45 @@ impl Program {
     pub fn all_meta(&self, name: &str) -> Vec<String> {
         self.meta.get(name).cloned().unwrap_or_default()
     }
+
+    /// Lower this program into a [`crate::compiled::CompiledProgram`]: a
+    /// flattened, pre-resolved form that `eval_compiled` can run without
+    /// re-matching every `Cmd` on each call. Worth paying for once (e.g.
+    /// when a `random_beat`/`mutate` result is about to be evaluated many
+    /// times per frame) rather than on every `eval_beat` call. `div_by_zero`
+    /// is baked into the compiled `Div`/`Mod` instructions, mirroring the
+    /// policy `eval_beat` would otherwise be passed on every call.
+    pub fn compile_exec(&self, div_by_zero: DivideByZeroPolicy) -> crate::compiled::CompiledProgram {
+        crate::compiled::CompiledProgram::new(&self.cmds, div_by_zero)
+    }
+
+    /// Run this program once per `t` in `t_range` and collect the results as
+    /// text, normalizing each output value into a Unicode scalar value the
+    /// same way `Chr` does (so a bytebeat written without `Chr` still
+    /// produces *something* displayable instead of panicking or failing).
+    /// Mouse/screen/keyboard inputs are held at zero, since text mode has no
+    /// interactive surface.
+    pub fn eval_text(&self, t_range: std::ops::Range<i64>) -> String {
+        let mut stack = Vec::new();
+        t_range
+            .map(|t| {
+                let val = eval_beat(
+                    &mut stack,
+                    self,
+                    t,
+                    0i64,
+                    0i64,
+                    0i64,
+                    0i64,
+                    0i64,
+                    0i64,
+                    DivideByZeroPolicy::SilentZero,
+                )
+                .unwrap_or(Val::I(0));
+                norm_char(val.into())
+            })
+            .collect()
+    }
 }
 
 pub fn compile(cmds: Vec<Cmd>) -> Result<Program, CompileError> {
@@ -146,32 +212,73 @@ pub fn compile(cmds: Vec<Cmd>) -> Result<Program, CompileError> {
                 .push(v.clone());
         }
     }
-    // Validate the bytebeat by checking that the stack does not get popped when empty
-    let mut stack_size = 0;
+    // Validate that Loop/End blocks are balanced, and that each loop's body
+    // has a net stack_change of zero (so repeating it any number of times
+    // cannot change the stack's size).
+    let mut loop_ends = HashMap::new();
+    let mut open_loops = Vec::new();
     let mut error_kind = None;
     for (index, cmd) in cmds.iter().enumerate() {
-        // TODO: Check if this works generally. This might not work on instructions
-        // that have a minimum stack size.
-        // If the stack would end up with a negitive size, then the stack clearly
-        // has underflowed. We also check if it equals zero, since any instruction
-        // that does something useful will need to pop at least one instruction
-        if stack_size + cmd.stack_change() <= 0 {
-            error_kind = Some(ErrorKind::UnderflowedStack { index, stack_size });
-            break;
+        match cmd {
+            Loop(_) => open_loops.push(index),
+            End => match open_loops.pop() {
+                Some(start) => {
+                    let net_stack_change: isize =
+                        cmds[start + 1..index].iter().map(Cmd::stack_change).sum();
+                    if net_stack_change != 0 {
+                        error_kind = Some(ErrorKind::UnbalancedLoopBody {
+                            loop_index: start,
+                            net_stack_change,
+                        });
+                        break;
+                    }
+                    loop_ends.insert(start, index);
+                }
+                None => {
+                    error_kind = Some(ErrorKind::UnmatchedEnd { index });
+                    break;
+                }
+            },
+            _ => (),
         }
-        // Do this after the if statement since we want to record the stack_size
-        // before applying the effect of the operator.
-        stack_size += cmd.stack_change();
     }
+    if error_kind.is_none() {
+        if let Some(&index) = open_loops.first() {
+            error_kind = Some(ErrorKind::UnmatchedLoop { index });
+        }
+    }
+
+    // Validate the bytebeat by checking that the stack does not get popped when empty
+    if error_kind.is_none() {
+        let mut stack_size = 0;
+        for (index, cmd) in cmds.iter().enumerate() {
+            // TODO: Check if this works generally. This might not work on instructions
+            // that have a minimum stack size.
+            // If the stack would end up with a negitive size, then the stack clearly
+            // has underflowed. We also check if it equals zero, since any instruction
+            // that does something useful will need to pop at least one instruction
+            if stack_size + cmd.stack_change() <= 0 {
+                error_kind = Some(ErrorKind::UnderflowedStack { index, stack_size });
+                break;
+            }
+            // Do this after the if statement since we want to record the stack_size
+            // before applying the effect of the operator.
+            stack_size += cmd.stack_change();
+        }
 
-    // Disallow programs which end up with an empty stack, because there is
-    // nothing to return when this happens (ex: programs consisting of only comments)
-    if stack_size == 0 && error_kind.is_none() {
-        error_kind = Some(ErrorKind::EmptyProgram);
+        // Disallow programs which end up with an empty stack, because there is
+        // nothing to return when this happens (ex: programs consisting of only comments)
+        if stack_size == 0 && error_kind.is_none() {
+            error_kind = Some(ErrorKind::EmptyProgram);
+        }
     }
 
     match error_kind {
-        None => Ok(Program { cmds, meta }),
+        None => Ok(Program {
+            cmds,
+            meta,
+            loop_ends,
+        }),
         Some(error_kind) => Err(CompileError { cmds, error_kind }),
     }
 }
@@ -215,6 +322,20 @@ impl<'a> std::fmt::Display for CompileError {
                 self.cmds[index], index, stack_size
             ),
             EmptyProgram => write!(fmt, "Program is empty: {:?}", self.cmds),
+            UnmatchedLoop { index } => {
+                write!(fmt, "Loop at index {} has no matching End", index)
+            }
+            UnmatchedEnd { index } => {
+                write!(fmt, "End at index {} has no matching Loop", index)
+            }
+            UnbalancedLoopBody {
+                loop_index,
+                net_stack_change,
+            } => write!(
+                fmt,
+                "Loop at index {} has a body whose stack_change is {} (expected 0)",
+                loop_index, net_stack_change
+            ),
         }
     }
 }
@@ -224,6 +345,12 @@ impl<'a> std::fmt::Display for CompileError {
 pub enum ErrorKind {
     UnderflowedStack { index: usize, stack_size: isize },
     EmptyProgram,
+    UnmatchedLoop { index: usize },
+    UnmatchedEnd { index: usize },
+    UnbalancedLoopBody {
+        loop_index: usize,
+        net_stack_change: isize,
+    },
 }
 
 /// A bytebeat value which is either an i64 or f64. This type allows for integer
@@ -340,14 +467,58 @@ macro_rules! stack_op {
     // we will pop 3 then 2 and do 3 - 2.
      ($stack:ident { $var:ident : $t:ty $(, $rvar:ident : $rt:ty)* }) => {
         stack_op!($stack { $($rvar : $rt),* });
-        let $var: $t = $stack.pop().unwrap().into();
+        let $var: $t = $stack.pop().ok_or(Trap::StackUnderflow)?.into();
+    }
+}
+
+/// A runtime error raised while evaluating a program. Unlike `CompileError`,
+/// which rejects a program before it ever runs, a `Trap` can only happen for
+/// hand-written programs: anything produced by `compile` on a machine-built
+/// `Vec<Cmd>` (e.g. `random_beat`/`mutate`) should never trip
+/// `StackUnderflow`/`ArrayIndexOutOfStack`, since `compile` already verifies
+/// the stack never underflows for a *fixed*-size `Arr`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trap {
+    /// An instruction tried to pop a value, but the stack was empty.
+    StackUnderflow,
+    /// An `Arr(size)` tried to read `size` values off the stack, but fewer
+    /// than `size` values (other than the index itself) were available.
+    ArrayIndexOutOfStack,
+    /// A `Bi(Div)`/`Bi(Mod)`/`BiFloat(DivF)`/`BiFloat(ModF)` divided by zero,
+    /// and `DivideByZeroPolicy::Trap` was in effect.
+    DivideByZero,
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use Trap::*;
+        match self {
+            StackUnderflow => write!(fmt, "Attempted to pop from an empty stack"),
+            ArrayIndexOutOfStack => write!(fmt, "Arr op read past the bottom of the stack"),
+            DivideByZero => write!(fmt, "Division or modulo by zero"),
+        }
     }
 }
 
+/// Controls what `Bi(Div)`/`Bi(Mod)`/`BiFloat(DivF)`/`BiFloat(ModF)` do when
+/// the divisor is zero.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DivideByZeroPolicy {
+    /// Evaluate to zero, matching the historical (pre-`Trap`) behavior.
+    SilentZero,
+    /// Raise `Trap::DivideByZero`.
+    Trap,
+}
+
 /// Evaluate a given program with the given values.
 /// `stack` takes a mutable reference to a vector, but does not actually care\
 /// about the contents of that vector. It will clear anything that was previously
 /// in the vector.
+///
+/// Returns `Err(Trap)` instead of panicking if the program pops more values
+/// than are available, or (depending on `div_by_zero`) divides by zero. This
+/// can only happen for a hand-written `Vec<Cmd>`; a `Program` obtained from
+/// `compile` normally never traps.
 pub fn eval_beat<T: Into<Val>>(
     stack: &mut Vec<Val>,
     program: &Program,
@@ -358,7 +529,8 @@ pub fn eval_beat<T: Into<Val>>(
     screen_y: T,
     key_x: T,
     key_y: T,
-) -> Val {
+    div_by_zero: DivideByZeroPolicy,
+) -> Result<Val, Trap> {
     use BiFloatType::*;
     use BiType::*;
     use Cmd::*;
@@ -375,9 +547,33 @@ pub fn eval_beat<T: Into<Val>>(
     let key_y = key_y.into();
     // Clear the stack, we don't actually care about the contents of it.
     stack.clear();
-    // Run the program!
-    for cmd in &program.cmds {
+    // Run the program! We step through `cmds` by index (rather than a plain
+    // `for` loop) so that `End` can jump back to the start of its `Loop` to
+    // repeat the body, or Loop(0) can jump straight past an empty body.
+    let mut pc = 0;
+    // (index of the opening Loop, iterations remaining) for every currently-open Loop.
+    let mut loop_stack: Vec<(usize, usize)> = Vec::new();
+    while pc < program.cmds.len() {
+        let cmd = &program.cmds[pc];
         match *cmd {
+            Loop(0) => {
+                pc = *program
+                    .loop_ends
+                    .get(&pc)
+                    .expect("compile() already verified Loop/End blocks are balanced")
+            }
+            Loop(iterations) => loop_stack.push((pc, iterations)),
+            End => {
+                let (start, remaining) = loop_stack
+                    .last_mut()
+                    .expect("compile() already verified Loop/End blocks are balanced");
+                *remaining -= 1;
+                if *remaining > 0 {
+                    pc = *start;
+                } else {
+                    loop_stack.pop();
+                }
+            }
             Var(Frame) => stack_op!(stack { } => t),
             Var(MouseX) => stack_op!(stack { } => mouse_x),
             Var(MouseY) => stack_op!(stack { } => mouse_y),
@@ -388,14 +584,29 @@ pub fn eval_beat<T: Into<Val>>(
             Literal(NumF(y)) => stack_op!( stack { } => y),
             Literal(NumI(y)) => stack_op!( stack { } => y),
             Literal(Hex(y)) => stack_op!( stack { } => y),
+            Literal(Char(c)) => stack_op!( stack { } => c as i64),
             Bi(Add) => stack_op!(stack { a: i64, b: i64 } => a.wrapping_add(b)),
             Bi(Sub) => stack_op!(stack { a: i64, b: i64 } => a.wrapping_sub(b)),
             Bi(Mul) => stack_op!(stack { a: i64, b: i64 } => a.wrapping_mul(b)),
             Bi(Div) => stack_op!(stack { a: i64, b: i64 } => {
-                if b == 0 { 0 } else { a.wrapping_div(b) }
+                if b == 0 {
+                    match div_by_zero {
+                        DivideByZeroPolicy::SilentZero => 0,
+                        DivideByZeroPolicy::Trap => return Err(Trap::DivideByZero),
+                    }
+                } else {
+                    a.wrapping_div(b)
+                }
             }),
             Bi(Mod) => stack_op!(stack { a: i64, b: i64 } => {
-                if b == 0 { 0 } else { a.wrapping_rem(b) }
+                if b == 0 {
+                    match div_by_zero {
+                        DivideByZeroPolicy::SilentZero => 0,
+                        DivideByZeroPolicy::Trap => return Err(Trap::DivideByZero),
+                    }
+                } else {
+                    a.wrapping_rem(b)
+                }
             }),
             Bi(Shl) => stack_op!(stack { a: i64, b: i64 } => a << (((b % 64) + 64) % 64)),
             Bi(Shr) => stack_op!(stack { a: i64, b: i64 } => {
@@ -411,15 +622,30 @@ pub fn eval_beat<T: Into<Val>>(
             Trig(Sin) => stack_op!(stack { a: f64 } => a.sin()),
             Trig(Cos) => stack_op!(stack { a: f64 } => a.cos()),
             Trig(Tan) => stack_op!(stack { a: f64 } => a.tan()),
+            Chr => stack_op!(stack { a: i64 } => norm_char(a) as i64),
             BiFloat(Pow) => stack_op!(stack { a: f64, b: f64 } => a.powf(b)),
             BiFloat(AddF) => stack_op!(stack { a: f64, b: f64 } => a + b),
             BiFloat(SubF) => stack_op!(stack { a: f64, b: f64 } => a - b),
             BiFloat(MulF) => stack_op!(stack { a: f64, b: f64 } => a * b),
             BiFloat(DivF) => stack_op!(stack { a: f64, b: f64 } => {
-                if b == 0.0 { 0.0 } else { a / b }
+                if b == 0.0 {
+                    match div_by_zero {
+                        DivideByZeroPolicy::SilentZero => 0.0,
+                        DivideByZeroPolicy::Trap => return Err(Trap::DivideByZero),
+                    }
+                } else {
+                    a / b
+                }
             }),
             BiFloat(ModF) => stack_op!(stack { a: f64, b: f64 } => {
-                if b == 0.0 { 0.0 } else { a % b }
+                if b == 0.0 {
+                    match div_by_zero {
+                        DivideByZeroPolicy::SilentZero => 0.0,
+                        DivideByZeroPolicy::Trap => return Err(Trap::DivideByZero),
+                    }
+                } else {
+                    a % b
+                }
             }),
             Comp(Lt) => stack_op!(stack { a: Val, b: Val } => a < b),
             Comp(Gt) => stack_op!(stack { a: Val, b: Val } => a > b),
@@ -432,7 +658,10 @@ pub fn eval_beat<T: Into<Val>>(
             }),
             Arr(0) => stack.push(0.into()),
             Arr(size) => {
-                let index: i64 = stack.pop().unwrap().into();
+                let index: i64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+                if size > stack.len() {
+                    return Err(Trap::ArrayIndexOutOfStack);
+                }
                 // We want to split off from the end, so we must subtract here.
                 let split_index = stack.len() - size;
                 let vec = stack.split_off(split_index);
@@ -445,8 +674,9 @@ pub fn eval_beat<T: Into<Val>>(
             // These have no runtime effect
             Meta(..) | Comment(..) => (),
         }
+        pc += 1;
     }
-    stack.pop().unwrap()
+    stack.pop().ok_or(Trap::StackUnderflow)
 }
 
 /// Attempt to parse a text string containing a bytebeat.
@@ -495,7 +725,17 @@ pub fn parse_beat(text: &str) -> Result<Vec<Cmd>, ParseError> {
             "==" => Ok(Comp(Eq)),
             "!=" => Ok(Comp(Neq)),
             "?" => Ok(Cond),
+            "chr" => Ok(Chr),
+            "end" => Ok(End),
             x if x.starts_with('[') => x[1..].parse().map(Arr).map_err(|_| BadArr(x, i)),
+            x if x.starts_with("loop(") && x.ends_with(')') => x[5..x.len() - 1]
+                .parse()
+                .map(Loop)
+                .map_err(|_| BadLoop(x, i)),
+            x if x.starts_with('\'') && x.ends_with('\'') && x.len() >= 3 => parse_char_literal(x)
+                .map(Char)
+                .map(Literal)
+                .ok_or(BadChar(x, i)),
             x if x.starts_with('!') && x.contains(':') => {
                 let mut parts = x[1..].split(':');
                 Ok(Meta(
@@ -525,9 +765,36 @@ pub fn parse_beat(text: &str) -> Result<Vec<Cmd>, ParseError> {
         .collect()
 }
 
+/// Parse a `'c'`-style token (with `c` already checked to be non-empty and
+/// quote-delimited) into the character it denotes, supporting the usual
+/// `\n`/`\t`/`\r`/`\0`/`\\`/`\'` escapes.
+fn parse_char_literal(token: &str) -> Option<char> {
+    let inner = &token[1..token.len() - 1];
+    let mut chars = inner.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            other => other,
+        },
+        c => c,
+    };
+    if chars.next().is_some() {
+        None
+    } else {
+        Some(c)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ParseError<'a> {
     BadArr(&'a str, usize),
+    BadLoop(&'a str, usize),
+    BadChar(&'a str, usize),
     UnknownToken(&'a str, usize),
 }
 
@@ -536,6 +803,8 @@ impl<'a> std::fmt::Display for ParseError<'a> {
         use ParseError::*;
         match *self {
             BadArr(token, index) => write!(fmt, "Bad Array op: {}, index: {}", token, index),
+            BadLoop(token, index) => write!(fmt, "Bad Loop op: {}, index: {}", token, index),
+            BadChar(token, index) => write!(fmt, "Bad Char literal: {}, index: {}", token, index),
             UnknownToken(token, index) => write!(fmt, "Unknown Token: {}, index: {}", token, index),
         }
     }
@@ -568,6 +837,20 @@ impl std::fmt::Display for Cmd {
             }
             Literal(NumI(y)) => write!(fmt, "{}", y),
             Literal(Hex(y)) => write!(fmt, "0x{:X}", y), // Write out as 0xHEX
+            // Only escape what `parse_char_literal` knows how to read back
+            // (`{:?}` would escape e.g. control chars as `\u{..}`, which
+            // `parse_char_literal` can't parse); anything else is written
+            // out as the literal char, since `char` is always valid UTF-8.
+            // A whitespace char other than these (e.g. a space) is written
+            // out as-is too, and `parse_beat`, which splits on whitespace,
+            // does not read it back as one token.
+            Literal(Char('\n')) => write!(fmt, "'\\n'"),
+            Literal(Char('\t')) => write!(fmt, "'\\t'"),
+            Literal(Char('\r')) => write!(fmt, "'\\r'"),
+            Literal(Char('\0')) => write!(fmt, "'\\0'"),
+            Literal(Char('\\')) => write!(fmt, "'\\\\'"),
+            Literal(Char('\'')) => write!(fmt, "'\\''"),
+            Literal(Char(c)) => write!(fmt, "'{}'", c),
             Bi(Add) => write!(fmt, "+"),
             Bi(Sub) => write!(fmt, "-"),
             Bi(Mul) => write!(fmt, "*"),
@@ -594,7 +877,10 @@ impl std::fmt::Display for Cmd {
             Comp(Eq) => write!(fmt, "=="),
             Comp(Neq) => write!(fmt, "!="),
             Cond => write!(fmt, "?"),
+            Chr => write!(fmt, "chr"),
             Arr(size) => write!(fmt, "[{}", size),
+            Loop(iterations) => write!(fmt, "loop({})", iterations),
+            End => write!(fmt, "end"),
             Meta(ref k, ref v) => write!(fmt, "!{}:{}", k, v),
             Comment(ref text) => write!(fmt, "#{}", text),
         }
@@ -659,6 +945,215 @@ pub fn random_beat(length: usize) -> Program {
     compile(program).expect("Expected valid program")
 }
 
+/// Shrink a compiled program into one that computes the same result for
+/// every input, but is cheaper for `eval_beat` to walk. This runs a single
+/// abstract-interpretation pass over `cmds`, tracking for each value
+/// currently on the stack whether it is a known constant and where (in the
+/// rewritten output) the instructions that produce it begin:
+///
+/// * Whenever a `Bi`/`BiFloat`/`Comp`/`Trig` op's operands are all known
+///   constants, the operand-plus-operator run is evaluated through the
+///   existing `eval_beat` kernel on a throwaway stack and replaced with a
+///   single `Literal`.
+/// * A handful of algebraic identities (`x 0 +`, `x 1 *`, `x 0 <<`/`>>`,
+///   `x x -`/`x x ^`) are folded even when `x` is not a constant, by
+///   deleting the instructions that produced the now-unneeded operand. This
+///   doubles as dead-code elimination: a deleted operand's producer is a
+///   value that would otherwise be pushed and never consumed before its
+///   pop, so tracking "where did this value's instructions start" is all
+///   that is needed to remove it.
+///
+/// `compile` is re-run on the result to re-validate the stack-balance
+/// invariant before handing back a `Program`.
+pub fn optimize(program: Program) -> Program {
+    let cmds = fold_and_peephole(&program.cmds);
+    compile(cmds).expect("optimize should preserve program validity")
+}
+
+pub(crate) fn literal_value(lit: LiteralType) -> Val {
+    match lit {
+        LiteralType::NumF(y) => Val::F(y),
+        LiteralType::NumI(y) | LiteralType::Hex(y) => Val::I(y),
+        LiteralType::Char(c) => Val::I(c as i64),
+    }
+}
+
+/// Normalize an arbitrary integer into a valid Unicode scalar value: values
+/// that don't fit in a `u32`, or that land in the surrogate range, fall back
+/// to the replacement character's codepoint instead of producing garbage.
+pub(crate) fn norm_char(value: i64) -> char {
+    u32::try_from(value)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or('\u{FFFD}')
+}
+
+fn literal_cmd(val: Val) -> Cmd {
+    match val {
+        Val::F(y) => Cmd::Literal(LiteralType::NumF(y)),
+        Val::I(y) => Cmd::Literal(LiteralType::NumI(y)),
+    }
+}
+
+/// Evaluate a self-contained run of instructions (no `Var`s, so the inputs
+/// don't matter) down to a single value, by compiling it and running it
+/// through `eval_beat` with throwaway input values.
+fn fold_value(fragment: Vec<Cmd>) -> Val {
+    let program = compile(fragment).expect("constant operands should fold to a valid program");
+    let mut stack = Vec::new();
+    eval_beat(
+        &mut stack,
+        &program,
+        0i64,
+        0i64,
+        0i64,
+        0i64,
+        0i64,
+        0i64,
+        0i64,
+        DivideByZeroPolicy::SilentZero,
+    )
+    .expect("a constant fragment built from Bi/BiFloat/Comp/Trig should not trap")
+}
+
+/// Fold `out[start..]` followed by `op` down to a single value.
+fn fold_fragment(out: &[Cmd], start: usize, op: Cmd) -> Val {
+    let mut fragment = out[start..].to_vec();
+    fragment.push(op);
+    fold_value(fragment)
+}
+
+fn fold_and_peephole(cmds: &[Cmd]) -> Vec<Cmd> {
+    use BiType::*;
+    use Cmd::*;
+
+    // For each value currently on the program's stack: whether it is a
+    // known constant, and the index into `out` where the instructions that
+    // produced it begin.
+    let mut stack: Vec<(Option<Val>, usize)> = Vec::new();
+    let mut out: Vec<Cmd> = Vec::with_capacity(cmds.len());
+    // Start index (into `out`) of each currently-open Loop, innermost last.
+    // A value produced before an open Loop can't be folded together with one
+    // produced inside its body: the body may run more than once, so the
+    // combined result isn't a single constant. Truncating `out` back past an
+    // open Loop would also delete it while its matching End is still to
+    // come, so every truncating transform below is gated on this.
+    let mut open_loops: Vec<usize> = Vec::new();
+    let crosses_open_loop =
+        |start: usize, open_loops: &[usize]| open_loops.last().is_some_and(|&s| start <= s);
+
+    for cmd in cmds {
+        match cmd {
+            Var(_) => {
+                stack.push((None, out.len()));
+                out.push(cmd.clone());
+            }
+            Literal(lit) => {
+                stack.push((Some(literal_value(*lit)), out.len()));
+                out.push(cmd.clone());
+            }
+            Loop(_) => {
+                open_loops.push(out.len());
+                out.push(cmd.clone());
+            }
+            End => {
+                open_loops.pop().expect("compile() already checked Loop/End are balanced");
+                out.push(cmd.clone());
+            }
+            Meta(..) | Comment(..) => out.push(cmd.clone()),
+            Bi(op) => {
+                let b = stack.pop().expect("compile() already checked stack balance");
+                let a = stack.pop().expect("compile() already checked stack balance");
+                if let (Some(_), Some(_)) = (a.0, b.0) {
+                    if !crosses_open_loop(a.1, &open_loops) {
+                        let value = fold_fragment(&out, a.1, cmd.clone());
+                        out.truncate(a.1);
+                        out.push(literal_cmd(value));
+                        stack.push((Some(value), a.1));
+                        continue;
+                    }
+                }
+                // `x op identity` -> keep only `x`'s instructions. This only
+                // ever discards `b`'s own (most recently pushed)
+                // instructions, so it is gated on `b` alone not reaching
+                // back past an open Loop.
+                let keep_a = !crosses_open_loop(b.1, &open_loops)
+                    && matches!(
+                    (op, b.0),
+                    (Add, Some(Val::I(0)))
+                        | (Sub, Some(Val::I(0)))
+                        | (Orr, Some(Val::I(0)))
+                        | (Xor, Some(Val::I(0)))
+                        | (Mul, Some(Val::I(1)))
+                        | (Shl, Some(Val::I(0)))
+                        | (Shr, Some(Val::I(0)))
+                );
+                if keep_a {
+                    out.truncate(b.1);
+                    stack.push(a);
+                    continue;
+                }
+                // `x * 0` -> `0`, whichever side the zero is on.
+                let mul_by_zero = matches!(op, Mul)
+                    && (matches!(a.0, Some(Val::I(0))) || matches!(b.0, Some(Val::I(0))));
+                // `x - x` / `x ^ x` -> `0`, when both sides are literally
+                // the same run of instructions (and thus always equal).
+                let self_cancel =
+                    matches!(op, Sub | Xor) && out[a.1..b.1] == out[b.1..];
+                if (mul_by_zero || self_cancel) && !crosses_open_loop(a.1, &open_loops) {
+                    out.truncate(a.1);
+                    out.push(Literal(LiteralType::NumI(0)));
+                    stack.push((Some(Val::I(0)), a.1));
+                    continue;
+                }
+                out.push(cmd.clone());
+                stack.push((None, a.1));
+            }
+            BiFloat(_) | Comp(_) => {
+                let b = stack.pop().expect("compile() already checked stack balance");
+                let a = stack.pop().expect("compile() already checked stack balance");
+                if a.0.is_some() && b.0.is_some() && !crosses_open_loop(a.1, &open_loops) {
+                    let value = fold_fragment(&out, a.1, cmd.clone());
+                    out.truncate(a.1);
+                    out.push(literal_cmd(value));
+                    stack.push((Some(value), a.1));
+                } else {
+                    out.push(cmd.clone());
+                    stack.push((None, a.1));
+                }
+            }
+            Trig(_) | Chr => {
+                let a = stack.pop().expect("compile() already checked stack balance");
+                if a.0.is_some() && !crosses_open_loop(a.1, &open_loops) {
+                    let value = fold_fragment(&out, a.1, cmd.clone());
+                    out.truncate(a.1);
+                    out.push(literal_cmd(value));
+                    stack.push((Some(value), a.1));
+                } else {
+                    out.push(cmd.clone());
+                    stack.push((None, a.1));
+                }
+            }
+            Cond => {
+                let _c = stack.pop().expect("compile() already checked stack balance");
+                let _b = stack.pop().expect("compile() already checked stack balance");
+                let a = stack.pop().expect("compile() already checked stack balance");
+                out.push(cmd.clone());
+                stack.push((None, a.1));
+            }
+            Arr(size) => {
+                let arity = size + 1;
+                let new_len = stack.len() - arity;
+                let start = stack[new_len].1;
+                stack.truncate(new_len);
+                out.push(cmd.clone());
+                stack.push((None, start));
+            }
+        }
+    }
+    out
+}
+
 /// Randomly alter a program. Each command in the program has `mutation_chance`
 /// probability of being changed to another command. Note that this will
 /// keep commands within the same "family". For example, Add may become Sub, but
@@ -676,7 +1171,10 @@ pub fn mutate(program: &Program, mutation_chance: f32) -> Program {
                 BiFloat(_) => BiFloat(rand::thread_rng().gen()),
                 Comp(_) => Comp(rand::thread_rng().gen()),
                 Cond => unimplemented!("Not used in random_beat!"),
+                Chr => unimplemented!("Not used in random_beat!"),
                 Arr(_) => unimplemented!("Not used in random_beat!"),
+                Loop(_) => unimplemented!("Not used in random_beat!"),
+                End => unimplemented!("Not used in random_beat!"),
                 Meta(_, _) => unimplemented!("Not used in random_beat!"),
                 Comment(_) => unimplemented!("Not used in random_beat!"),
             }
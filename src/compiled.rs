@@ -0,0 +1,317 @@
+//! A precompiled, "threaded code" form of a [`bytebeat::Program`].
+//!
+//! `eval_beat` sits on the hottest possible path (called once per pixel per
+//! frame), yet it re-matches every [`Cmd`] and re-derives the `Arr`
+//! split-off offset on every single call. [`CompiledProgram`] lowers a
+//! program's `Vec<Cmd>` once into a flat `Vec` of resolved closures, so
+//! running it is a sequence of direct calls instead of a big match
+//! re-dispatched per instruction per sample. This mirrors the codegen/flatten
+//! split that bytecode VMs (e.g. the BEAM, or holey-bytes) use to keep their
+//! interpreter loop out of the way of the instructions it's running.
+
+use crate::bytebeat::{
+    self, norm_char, BiFloatType, BiType, Cmd, CompType, DivideByZeroPolicy, Trap, TrigType, Val,
+    VarType,
+};
+
+/// The inputs an [`eval_compiled`] run needs, gathered up-front instead of
+/// being threaded through as separate parameters.
+pub struct Inputs {
+    pub t: Val,
+    pub mouse_x: Val,
+    pub mouse_y: Val,
+    pub screen_x: Val,
+    pub screen_y: Val,
+    pub key_x: Val,
+    pub key_y: Val,
+}
+
+type Op = Box<dyn Fn(&mut Vec<Val>, &Inputs) -> Result<(), Trap> + Send + Sync>;
+
+/// One lowered instruction. `Loop`/`End` can't be plain `Op` closures since
+/// they need to move the program counter instead of just touching the
+/// stack, so `eval_compiled` steps through these explicitly (mirroring
+/// `eval_beat`'s own pc-based loop).
+enum Instr {
+    Op(Op),
+    /// `end` is the index of the matching `Instr::End`, precomputed here so
+    /// a `Loop(0)` can jump straight past its body.
+    Loop { iterations: usize, end: usize },
+    End,
+}
+
+/// A [`bytebeat::Program`] lowered into a flat sequence of instructions.
+/// `Meta`/`Comment` no-ops are dropped entirely at this stage, since they
+/// have no runtime effect and would otherwise cost a no-op call.
+pub struct CompiledProgram {
+    instrs: Vec<Instr>,
+}
+
+impl CompiledProgram {
+    pub(crate) fn new(cmds: &[Cmd], div_by_zero: DivideByZeroPolicy) -> CompiledProgram {
+        let mut instrs = Vec::with_capacity(cmds.len());
+        // Indices (into `instrs`) of currently-open `Loop`s, so the matching
+        // `End` can patch in where its body ends.
+        let mut open_loops: Vec<usize> = Vec::new();
+        for cmd in cmds {
+            match cmd {
+                Cmd::Loop(iterations) => {
+                    open_loops.push(instrs.len());
+                    instrs.push(Instr::Loop {
+                        iterations: *iterations,
+                        end: usize::MAX, // patched in once the matching End is seen
+                    });
+                }
+                Cmd::End => {
+                    let start = open_loops
+                        .pop()
+                        .expect("compile() already verified Loop/End blocks are balanced");
+                    let end = instrs.len();
+                    if let Instr::Loop { end: slot, .. } = &mut instrs[start] {
+                        *slot = end;
+                    }
+                    instrs.push(Instr::End);
+                }
+                _ => {
+                    if let Some(op) = compile_cmd(cmd, div_by_zero) {
+                        instrs.push(Instr::Op(op));
+                    }
+                }
+            }
+        }
+        CompiledProgram { instrs }
+    }
+}
+
+fn push(val: Val) -> Op {
+    Box::new(move |stack, _| {
+        stack.push(val);
+        Ok(())
+    })
+}
+
+fn compile_cmd(cmd: &Cmd, div_by_zero: DivideByZeroPolicy) -> Option<Op> {
+    use BiFloatType::*;
+    use BiType::*;
+    use Cmd::*;
+    use CompType::*;
+    use TrigType::*;
+    use VarType::*;
+
+    let op: Op = match *cmd {
+        Var(Frame) => Box::new(|stack, inputs| {
+            stack.push(inputs.t);
+            Ok(())
+        }),
+        Var(MouseX) => Box::new(|stack, inputs| {
+            stack.push(inputs.mouse_x);
+            Ok(())
+        }),
+        Var(MouseY) => Box::new(|stack, inputs| {
+            stack.push(inputs.mouse_y);
+            Ok(())
+        }),
+        Var(ScreenX) => Box::new(|stack, inputs| {
+            stack.push(inputs.screen_x);
+            Ok(())
+        }),
+        Var(ScreenY) => Box::new(|stack, inputs| {
+            stack.push(inputs.screen_y);
+            Ok(())
+        }),
+        Var(KeyboardX) => Box::new(|stack, inputs| {
+            stack.push(inputs.key_x);
+            Ok(())
+        }),
+        Var(KeyboardY) => Box::new(|stack, inputs| {
+            stack.push(inputs.key_y);
+            Ok(())
+        }),
+        Literal(lit) => push(bytebeat::literal_value(lit)),
+        Bi(Add) => bi_int(|a, b| a.wrapping_add(b)),
+        Bi(Sub) => bi_int(|a, b| a.wrapping_sub(b)),
+        Bi(Mul) => bi_int(|a, b| a.wrapping_mul(b)),
+        Bi(Div) => bi_int_fallible(move |a, b| {
+            if b == 0 {
+                match div_by_zero {
+                    DivideByZeroPolicy::SilentZero => Ok(0),
+                    DivideByZeroPolicy::Trap => Err(Trap::DivideByZero),
+                }
+            } else {
+                Ok(a.wrapping_div(b))
+            }
+        }),
+        Bi(Mod) => bi_int_fallible(move |a, b| {
+            if b == 0 {
+                match div_by_zero {
+                    DivideByZeroPolicy::SilentZero => Ok(0),
+                    DivideByZeroPolicy::Trap => Err(Trap::DivideByZero),
+                }
+            } else {
+                Ok(a.wrapping_rem(b))
+            }
+        }),
+        Bi(Shl) => bi_int(|a, b| a << (((b % 64) + 64) % 64)),
+        Bi(Shr) => bi_int(|a, b| {
+            let mut b = b % 64;
+            if b < 0 {
+                b += 64;
+            }
+            a >> b
+        }),
+        Bi(And) => bi_int(|a, b| a & b),
+        Bi(Orr) => bi_int(|a, b| a | b),
+        Bi(Xor) => bi_int(|a, b| a ^ b),
+        Trig(Sin) => un_float(f64::sin),
+        Trig(Cos) => un_float(f64::cos),
+        Trig(Tan) => un_float(f64::tan),
+        Chr => un_int(|a| norm_char(a) as i64),
+        BiFloat(Pow) => bi_float(f64::powf),
+        BiFloat(AddF) => bi_float(|a, b| a + b),
+        BiFloat(SubF) => bi_float(|a, b| a - b),
+        BiFloat(MulF) => bi_float(|a, b| a * b),
+        BiFloat(DivF) => bi_float_fallible(move |a, b| {
+            if b == 0.0 {
+                match div_by_zero {
+                    DivideByZeroPolicy::SilentZero => Ok(0.0),
+                    DivideByZeroPolicy::Trap => Err(Trap::DivideByZero),
+                }
+            } else {
+                Ok(a / b)
+            }
+        }),
+        BiFloat(ModF) => bi_float_fallible(move |a, b| {
+            if b == 0.0 {
+                match div_by_zero {
+                    DivideByZeroPolicy::SilentZero => Ok(0.0),
+                    DivideByZeroPolicy::Trap => Err(Trap::DivideByZero),
+                }
+            } else {
+                Ok(a % b)
+            }
+        }),
+        Comp(Lt) => comp(|a, b| a < b),
+        Comp(Gt) => comp(|a, b| a > b),
+        Comp(Leq) => comp(|a, b| a <= b),
+        Comp(Geq) => comp(|a, b| a >= b),
+        Comp(Eq) => comp(|a, b| a == b),
+        Comp(Neq) => comp(|a, b| a != b),
+        Cond => Box::new(|stack, _| {
+            let cond: bool = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+            let b = stack.pop().ok_or(Trap::StackUnderflow)?;
+            let a = stack.pop().ok_or(Trap::StackUnderflow)?;
+            stack.push(if cond { a } else { b });
+            Ok(())
+        }),
+        // `size` is baked into the closure here instead of being matched
+        // out of the `Cmd` on every call; the split-off point itself is
+        // still `stack.len() - size`, computed per call.
+        Arr(0) => Box::new(|stack, _| {
+            stack.push(Val::I(0));
+            Ok(())
+        }),
+        Arr(size) => Box::new(move |stack, _| {
+            let index: i64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+            if size > stack.len() {
+                return Err(Trap::ArrayIndexOutOfStack);
+            }
+            let split_index = stack.len() - size;
+            let values = stack.split_off(split_index);
+            let size = size as i64;
+            let index = ((index % size) + size) % size;
+            stack.push(values[index as usize]);
+            Ok(())
+        }),
+        Meta(..) | Comment(..) => return None,
+        Loop(..) | End => unreachable!("Loop/End are lowered directly by CompiledProgram::new"),
+    };
+    Some(op)
+}
+
+fn bi_int(f: impl Fn(i64, i64) -> i64 + Send + Sync + 'static) -> Op {
+    bi_int_fallible(move |a, b| Ok(f(a, b)))
+}
+
+fn bi_int_fallible(f: impl Fn(i64, i64) -> Result<i64, Trap> + Send + Sync + 'static) -> Op {
+    Box::new(move |stack, _| {
+        let b: i64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+        let a: i64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+        stack.push(f(a, b)?.into());
+        Ok(())
+    })
+}
+
+fn un_int(f: impl Fn(i64) -> i64 + Send + Sync + 'static) -> Op {
+    Box::new(move |stack, _| {
+        let a: i64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+        stack.push(f(a).into());
+        Ok(())
+    })
+}
+
+fn bi_float(f: impl Fn(f64, f64) -> f64 + Send + Sync + 'static) -> Op {
+    bi_float_fallible(move |a, b| Ok(f(a, b)))
+}
+
+fn bi_float_fallible(f: impl Fn(f64, f64) -> Result<f64, Trap> + Send + Sync + 'static) -> Op {
+    Box::new(move |stack, _| {
+        let b: f64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+        let a: f64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+        stack.push(f(a, b)?.into());
+        Ok(())
+    })
+}
+
+fn un_float(f: impl Fn(f64) -> f64 + Send + Sync + 'static) -> Op {
+    Box::new(move |stack, _| {
+        let a: f64 = stack.pop().ok_or(Trap::StackUnderflow)?.into();
+        stack.push(f(a).into());
+        Ok(())
+    })
+}
+
+fn comp(f: impl Fn(Val, Val) -> bool + Send + Sync + 'static) -> Op {
+    Box::new(move |stack, _| {
+        let b: Val = stack.pop().ok_or(Trap::StackUnderflow)?;
+        let a: Val = stack.pop().ok_or(Trap::StackUnderflow)?;
+        stack.push(f(a, b).into());
+        Ok(())
+    })
+}
+
+/// Run a [`CompiledProgram`], mirroring `eval_beat`'s contract: `stack` is
+/// cleared and used as scratch space, and the final value left on it is
+/// returned. Returns `Err(Trap)` instead of panicking under the same
+/// conditions `eval_beat` would trap (see `Trap`'s docs); the
+/// `DivideByZeroPolicy` this program was compiled with is already baked into
+/// its `Div`/`Mod` instructions.
+pub fn eval_compiled(
+    program: &CompiledProgram,
+    stack: &mut Vec<Val>,
+    inputs: &Inputs,
+) -> Result<Val, Trap> {
+    stack.clear();
+    let mut pc = 0;
+    // (index of the opening Loop, iterations remaining) for every currently-open Loop.
+    let mut loop_stack: Vec<(usize, usize)> = Vec::new();
+    while pc < program.instrs.len() {
+        match &program.instrs[pc] {
+            Instr::Op(op) => op(stack, inputs)?,
+            Instr::Loop { iterations: 0, end } => pc = *end,
+            Instr::Loop { iterations, .. } => loop_stack.push((pc, *iterations)),
+            Instr::End => {
+                let (start, remaining) = loop_stack
+                    .last_mut()
+                    .expect("compile() already verified Loop/End blocks are balanced");
+                *remaining -= 1;
+                if *remaining > 0 {
+                    pc = *start;
+                } else {
+                    loop_stack.pop();
+                }
+            }
+        }
+        pc += 1;
+    }
+    stack.pop().ok_or(Trap::StackUnderflow)
+}
@@ -27,3 +27,68 @@ pub fn evaluate_mandelbrot(
         iters += 1;
     }
 }
+
+/// Parameters for a multibrot/Julia escape-time evaluation.
+#[derive(Clone, Copy, Debug)]
+pub struct MandelbrotParams {
+    /// The exponent in `z = z.powu(power) + c`. `2` gives the classic
+    /// Mandelbrot/Julia set; higher values give a "multibrot".
+    pub power: u32,
+    /// If set, `c` is held fixed at this value and the point being
+    /// evaluated is used as the starting `z` instead (a Julia set). If
+    /// unset, `c` is the point itself and `z` starts at the origin (the
+    /// classic Mandelbrot/multibrot set).
+    pub julia_c: Option<Complex<f64>>,
+    pub max_iters: usize,
+    pub escape_radius: f64,
+}
+
+impl MandelbrotParams {
+    /// The classic Mandelbrot set: `power` 2, no fixed `julia_c`.
+    pub fn new(max_iters: usize, escape_radius: f64) -> MandelbrotParams {
+        MandelbrotParams {
+            power: 2,
+            julia_c: None,
+            max_iters,
+            escape_radius,
+        }
+    }
+}
+
+// Like `evaluate_mandelbrot`, but generalized to multibrot powers and Julia
+// sets, and returning a continuous (smoothed) escape value instead of an
+// integer iteration count. An integer count bands badly when mapped to
+// color, since every pixel that escapes on the same iteration gets the
+// exact same color; the fractional part here measures how far past
+// `escape_radius` the point overshot on its last iteration, which smooths
+// the boundary between bands. `escape_radius` should be large (e.g. >= 2^8)
+// for this smoothing term to be accurate.
+pub fn evaluate_mandelbrot_smooth(point: Complex<f64>, params: MandelbrotParams) -> Option<f64> {
+    let MandelbrotParams {
+        power,
+        julia_c,
+        max_iters,
+        escape_radius,
+    } = params;
+    // Julia set: c is fixed, and the point being evaluated is the starting z.
+    // Mandelbrot/multibrot set: c is the point, and z starts at the origin.
+    let c = julia_c.unwrap_or(point);
+    let mut z = julia_c.map_or(Complex::new(0.0, 0.0), |_| point);
+    let mut iters = 0;
+    loop {
+        // Bailout -- reached max iterations
+        if iters >= max_iters {
+            return None;
+        }
+
+        // Bailout -- point escaped the escape radius
+        let magnitude = z.norm();
+        if magnitude > escape_radius {
+            let smoothed = iters as f64 + 1.0 - (magnitude.ln().ln() / (power as f64).ln());
+            return Some(smoothed);
+        }
+
+        z = z.powu(power) + c;
+        iters += 1;
+    }
+}
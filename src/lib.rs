@@ -0,0 +1,4 @@
+pub mod bf;
+pub mod bytebeat;
+pub mod compiled;
+pub mod fractal;
@@ -1,43 +1,238 @@
-use std::convert::Infallible;
+use std::convert::{Infallible, TryFrom};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 
-use serde::Deserialize;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use broken_field::bytebeat;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
 use sqlx::{pool::PoolConnection, Sqlite, SqlitePool};
+use tokio::sync::{mpsc, oneshot};
 use warp::{
-    hyper::{StatusCode, Uri},
+    hyper::{header, StatusCode, Uri},
     reject::Reject,
     Filter, Rejection, Reply,
 };
 
-struct CouldntConnect(sqlx::Error);
-impl std::fmt::Debug for CouldntConnect {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Couldn't connect to the database: {}", self.0)
+/// Every way a request into this service can fail, in one place so
+/// `handle_rejection` has a single enum to match on instead of a pile of
+/// one-off reject structs. `warp`'s own native rejections (not-found,
+/// wrong method, unparseable body) are translated into this enum too, so
+/// every error response built from it has the same JSON shape.
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[error("couldn't connect to the database: {0}")]
+    Connection(#[source] sqlx::Error),
+    #[error("error running a database query: {0}")]
+    Query(#[source] sqlx::Error),
+    #[error("invalid slug: {0:?}")]
+    InvalidSlug(String),
+    #[error("slug already points elsewhere: {0:?}")]
+    SlugTaken(String),
+    #[error("missing or invalid Authorization header")]
+    Unauthorized,
+    #[error("invalid redirect code: {0} (must be 301, 302, 307, or 308)")]
+    InvalidRedirectCode(u16),
+    #[error("the shortened URL could not be found")]
+    NotFound,
+    #[error("the method is not allowed")]
+    MethodNotAllowed,
+    #[error("the request body could not be parsed: {0}")]
+    InvalidBody(String),
+    #[error("url does not decode to a valid BROKEN_FIELD program: {0}")]
+    InvalidProgram(String),
+}
+impl Reject for Error {}
+
+#[derive(Deserialize)]
+struct URLParams {
+    url: String,
+    slug: Option<String>,
+    /// The HTTP status code the GET redirect should be served with. Must be
+    /// one of the redirect-family codes (301/302/307/308); defaults to 307
+    /// (temporary redirect, preserving the request method) if omitted.
+    redirect_code: Option<u16>,
+}
+
+const DEFAULT_REDIRECT_CODE: i64 = 307;
+
+fn is_valid_redirect_code(code: u16) -> bool {
+    matches!(code, 301 | 302 | 307 | 308)
+}
+
+// Turn a `redirect_code` value read back from the database into a `StatusCode`,
+// falling back to 307 for anything that isn't one of the redirect-family
+// codes `is_valid_redirect_code` allows in (the column should never actually
+// contain anything else, since `create` validates it up front).
+fn redirect_status(code: i64) -> StatusCode {
+    u16::try_from(code)
+        .ok()
+        .filter(|&code| is_valid_redirect_code(code))
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::TEMPORARY_REDIRECT)
+}
+
+// How many characters a vanity slug may contain.
+const MAX_SLUG_LEN: usize = 32;
+
+// How many times to retry a randomly-generated id on a `short_url` collision
+// before giving up and surfacing the error.
+const RANDOM_ID_RETRIES: usize = 5;
+
+fn slug_is_valid(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LEN
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Identifies which `shortened_url` column tripped a UNIQUE constraint
+// violation, so callers can tell a slug collision (`short_url`) apart from
+// this URL already having a short code under a different slug (`long_url`)
+// instead of conflating the two. `None` means this wasn't a UNIQUE
+// violation on either column we care about.
+fn unique_violation_column(err: &sqlx::Error) -> Option<&'static str> {
+    match err {
+        // SQLite's extended result code for a UNIQUE constraint violation.
+        sqlx::Error::Database(db_err) if db_err.code().is_some_and(|code| code == "2067") => {
+            let message = db_err.message();
+            if message.contains("shortened_url.short_url") {
+                Some("short_url")
+            } else if message.contains("shortened_url.long_url") {
+                Some("long_url")
+            } else {
+                None
+            }
+        }
+        _ => None,
     }
 }
-impl Reject for CouldntConnect {}
 
-struct QueryError(sqlx::Error);
-impl std::fmt::Debug for QueryError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error in running query: {}", self.0)
+// Looks up the short code an already-shortened `long_url` is stored under,
+// for the idempotent-return path when an insert loses a race (or was always
+// going to lose) to a prior row for the same URL.
+async fn existing_short_url(
+    conn: &mut PoolConnection<Sqlite>,
+    long_url: &str,
+) -> Result<Option<String>, Error> {
+    sqlx::query_scalar("SELECT short_url FROM shortened_url WHERE long_url = ?")
+        .bind(long_url)
+        .fetch_optional(conn)
+        .await
+        .map_err(Error::Query)
+}
+
+// The query keys the front end (https://a2aaron.github.io/BROKEN_FIELD/)
+// expects: `bytebeat` is the program source, base64-encoded then
+// percent-encoded; `color` is an orthogonal display setting. See the
+// original stub in `src/main.rs`'s `id_to_query_params` for the format
+// this mirrors.
+const EXPECTED_QUERY_KEYS: &[&str] = &["bytebeat", "color"];
+
+// How long a `bytebeat` program's decoded source may be before it's
+// rejected outright, well before it reaches the parser.
+const MAX_PROGRAM_LEN: usize = 4096;
+
+fn query_params(query: &str) -> impl Iterator<Item = (&str, &str)> {
+    query.split('&').filter_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next()?;
+        if key.is_empty() {
+            return None;
+        }
+        Some((key, parts.next().unwrap_or("")))
+    })
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    fn hex_digit(b: u8) -> Option<u8> {
+        match b {
+            b'0'..=b'9' => Some(b - b'0'),
+            b'a'..=b'f' => Some(b - b'a' + 10),
+            b'A'..=b'F' => Some(b - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let mut bytes = s.bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hi = hex_digit(bytes.next()?)?;
+                let lo = hex_digit(bytes.next()?)?;
+                decoded.push((hi << 4) | lo);
+            }
+            b'+' => decoded.push(b' '),
+            _ => decoded.push(b),
+        }
     }
+    String::from_utf8(decoded).ok()
 }
-impl Reject for QueryError {}
 
-#[derive(Deserialize)]
-struct URLParams {
-    url: String,
+// Confirms `query` (the `url` field of a `URLParams`) has the keys the
+// front end expects and that its `bytebeat` value decodes to source that
+// actually parses and compiles as a BROKEN_FIELD program. This is the
+// only thing standing between a short link and a payload that renders to
+// nothing (or a client-side crash) when somebody visits it.
+fn validate_broken_field_query(query: &str) -> Result<(), Error> {
+    for key in EXPECTED_QUERY_KEYS {
+        if !query_params(query).any(|(k, _)| k == *key) {
+            return Err(Error::InvalidProgram(format!(
+                "missing `{}` query parameter",
+                key
+            )));
+        }
+    }
+
+    let encoded = query_params(query)
+        .find(|(k, _)| *k == "bytebeat")
+        .map(|(_, v)| v)
+        .expect("presence of the `bytebeat` key was just checked above");
+
+    let decoded = percent_decode(encoded)
+        .ok_or_else(|| Error::InvalidProgram("`bytebeat` is not valid percent-encoding".into()))?;
+    let source = BASE64
+        .decode(&decoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| Error::InvalidProgram("`bytebeat` is not valid base64".into()))?;
+
+    if source.len() > MAX_PROGRAM_LEN {
+        return Err(Error::InvalidProgram(format!(
+            "program source is longer than {} characters",
+            MAX_PROGRAM_LEN
+        )));
+    }
+    if !source.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return Err(Error::InvalidProgram(
+            "program source contains characters outside the BROKEN_FIELD alphabet".into(),
+        ));
+    }
+
+    let cmds =
+        bytebeat::parse_beat(&source).map_err(|err| Error::InvalidProgram(err.to_string()))?;
+    bytebeat::compile(cmds)
+        .map_err(|err| Error::InvalidProgram(err.to_string()))?;
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
     let pool = SqlitePool::connect("example.db").await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS shortened_url
-                (short_url TEXT NOT NULL UNIQUE, long_url TEXT NOT NULL UNIQUE)",
-    )
-    .execute(&pool)
-    .await?;
+    // Runs in every build, debug included: it's what creates the schema on
+    // a fresh `example.db`, and applying already-applied migrations is a
+    // cheap no-op, so there's no real iteration-speed cost to skip.
+    sqlx::migrate!().run(&pool).await?;
+
+    let auth_key: Arc<str> =
+        Arc::from(std::env::var("BROKEN_FIELD_AUTH_KEY").expect(
+            "BROKEN_FIELD_AUTH_KEY must be set to the token required to mint short URLs",
+        ));
+
+    let service = SlugService::spawn(pool.clone());
 
     println!("nya");
     let home_page_redirect = warp::filters::method::get()
@@ -51,48 +246,149 @@ async fn main() -> Result<(), sqlx::Error> {
         .and(warp::path("BROKEN_FIELD"))
         .and(warp::path::param())
         .and(warp::path::end())
-        .and(with_db(pool.clone()))
-        .and_then(|id: String, pool: SqlitePool| async move {
-            let mut conn = pool
-                .acquire()
-                .await
-                .map_err(|err| warp::reject::custom(CouldntConnect(err)))?;
-
-            if let Some(query) = id_to_query_params(&id, &mut conn).await {
-                let path = format!("https://a2aaron.github.io/BROKEN_FIELD/?{}", query);
-                let uri = path.parse::<Uri>().unwrap();
-                // Note: this should be a permenant redirect in the actual live site.
-                Result::<_, Rejection>::Ok(warp::redirect::temporary(uri))
-            } else {
-                println!("Not found");
-                Err(warp::reject::not_found())
+        .and(with_service(service.clone()))
+        .and_then(|id: String, service: SlugService| async move {
+            match service.get(id).await.map_err(warp::reject::custom)? {
+                Some((query, redirect_code)) => {
+                    let path = format!("https://a2aaron.github.io/BROKEN_FIELD/?{}", query);
+                    let uri = path.parse::<Uri>().unwrap();
+                    let reply = warp::reply::with_header(
+                        warp::reply::with_status(warp::reply(), redirect_status(redirect_code)),
+                        header::LOCATION,
+                        uri.to_string(),
+                    );
+                    Result::<_, Rejection>::Ok(reply)
+                }
+                None => Err(warp::reject::custom(Error::NotFound)),
             }
         });
 
     let create = warp::filters::method::post()
         .and(warp::path("BROKEN_FIELD"))
         .and(warp::path::end())
+        .and(with_auth(Arc::clone(&auth_key)))
         .and(warp::filters::body::json())
         .and(with_db(pool.clone()))
-        .and_then(|json: URLParams, pool: SqlitePool| async move {
-            let mut conn = pool
-                .acquire()
-                .await
-                .map_err(|err| warp::reject::custom(CouldntConnect(err)))?;
+        .and(with_service(service.clone()))
+        .and_then(
+            |json: URLParams, pool: SqlitePool, service: SlugService| async move {
+                validate_broken_field_query(&json.url).map_err(warp::reject::custom)?;
 
-            let id = new_id();
+                let mut conn = pool
+                    .acquire()
+                    .await
+                    .map_err(|err| warp::reject::custom(Error::Connection(err)))?;
 
-            sqlx::query("INSERT OR IGNORE INTO shortened_url VALUES (?, ?)")
-                .bind(&id)
-                .bind(&json.url)
-                .execute(&mut conn)
-                .await
-                .map_err(|err| warp::reject::custom(QueryError(err)))?;
+                let redirect_code = match json.redirect_code {
+                    Some(code) if is_valid_redirect_code(code) => code as i64,
+                    Some(code) => {
+                        return Err(warp::reject::custom(Error::InvalidRedirectCode(code)))
+                    }
+                    None => DEFAULT_REDIRECT_CODE,
+                };
 
-            Result::<_, Rejection>::Ok(warp::reply::json(
-                &query_params_to_id(&json.url, &mut conn).await,
-            ))
-        });
+                let id = if let Some(slug) = json.slug {
+                    if !slug_is_valid(&slug) {
+                        return Err(warp::reject::custom(Error::InvalidSlug(slug)));
+                    }
+
+                    match service.get(slug.clone()).await.map_err(warp::reject::custom)? {
+                        // The slug is already in use, but for this exact URL: return it idempotently.
+                        Some((existing_url, _)) if existing_url == json.url => slug,
+                        Some(_) => return Err(warp::reject::custom(Error::SlugTaken(slug))),
+                        None => {
+                            // The cache may be stale, so the insert itself
+                            // still has to handle losing a race to another
+                            // request for the same slug.
+                            let result = service
+                                .put(slug.clone(), json.url.clone(), redirect_code)
+                                .await;
+                            match result {
+                                Ok(()) => slug,
+                                Err(err) => match unique_violation_column(&err) {
+                                    // Lost the race from the comment above: someone
+                                    // else inserted this slug first. If it's for
+                                    // the same URL, return it idempotently just
+                                    // like the pre-insert cache check does;
+                                    // otherwise the slug really is taken.
+                                    Some("short_url") => {
+                                        match id_to_query_params(&slug, &mut conn)
+                                            .await
+                                            .map_err(warp::reject::custom)?
+                                        {
+                                            Some((existing_url, _))
+                                                if existing_url == json.url =>
+                                            {
+                                                slug
+                                            }
+                                            _ => {
+                                                return Err(warp::reject::custom(
+                                                    Error::SlugTaken(slug),
+                                                ))
+                                            }
+                                        }
+                                    }
+                                    // The slug itself was free; it's this
+                                    // URL that already has a short code
+                                    // under a different slug. Return that
+                                    // one instead of mislabeling it as the
+                                    // slug being taken.
+                                    Some("long_url") => {
+                                        match existing_short_url(&mut conn, &json.url)
+                                            .await
+                                            .map_err(warp::reject::custom)?
+                                        {
+                                            Some(existing) => existing,
+                                            None => {
+                                                return Err(warp::reject::custom(Error::Query(
+                                                    err,
+                                                )))
+                                            }
+                                        }
+                                    }
+                                    _ => return Err(warp::reject::custom(Error::Query(err))),
+                                },
+                            }
+                        }
+                    }
+                } else {
+                    let mut candidate = new_id();
+                    let mut attempts = 0;
+                    loop {
+                        let result = service
+                            .put(candidate.clone(), json.url.clone(), redirect_code)
+                            .await;
+                        match result {
+                            Ok(()) => break candidate,
+                            Err(err) => match unique_violation_column(&err) {
+                                Some("short_url") if attempts < RANDOM_ID_RETRIES => {
+                                    attempts += 1;
+                                    candidate = new_id();
+                                }
+                                // A random id can never resolve this by
+                                // retrying: this exact URL already has a
+                                // short code, so return it idempotently
+                                // instead of burning through retries.
+                                Some("long_url") => {
+                                    match existing_short_url(&mut conn, &json.url)
+                                        .await
+                                        .map_err(warp::reject::custom)?
+                                    {
+                                        Some(existing) => break existing,
+                                        None => {
+                                            return Err(warp::reject::custom(Error::Query(err)))
+                                        }
+                                    }
+                                }
+                                _ => return Err(warp::reject::custom(Error::Query(err))),
+                            },
+                        }
+                    }
+                };
+
+                Result::<_, Rejection>::Ok(warp::reply::json(&id))
+            },
+        );
 
     let routes = create.or(redirect.or(home_page_redirect).recover(handle_rejection));
 
@@ -101,15 +397,6 @@ async fn main() -> Result<(), sqlx::Error> {
     Ok(())
 }
 
-async fn query_params_to_id(url: &str, conn: &mut PoolConnection<Sqlite>) -> String {
-    let (id,) = sqlx::query_as("SELECT short_url FROM shortened_url WHERE long_url = ?")
-        .bind(&url)
-        .fetch_one(conn)
-        .await
-        .unwrap();
-    return id;
-}
-
 fn new_id() -> String {
     random_string::generate(
         12,
@@ -117,13 +404,15 @@ fn new_id() -> String {
     )
 }
 
-async fn id_to_query_params(id: &str, conn: &mut PoolConnection<Sqlite>) -> Option<String> {
-    sqlx::query_as("SELECT long_url FROM shortened_url WHERE short_url = ?")
+async fn id_to_query_params(
+    id: &str,
+    conn: &mut PoolConnection<Sqlite>,
+) -> Result<Option<(String, i64)>, Error> {
+    sqlx::query_as("SELECT long_url, redirect_code FROM shortened_url WHERE short_url = ?")
         .bind(&id)
         .fetch_optional(conn)
         .await
-        .unwrap()
-        .map(|(x,)| x)
+        .map_err(Error::Query)
 }
 
 fn with_db(
@@ -132,27 +421,208 @@ fn with_db(
     warp::any().map(move || db_pool.clone())
 }
 
+// Only the actor task in `SlugService::spawn` ever touches the cache, so
+// there's no need for a `Mutex` around it; handlers talk to it over `sender`
+// instead.
+enum CacheMessage {
+    Get(String, oneshot::Sender<Result<Option<(String, i64)>, Error>>),
+    Put {
+        short_url: String,
+        long_url: String,
+        redirect_code: i64,
+        reply: oneshot::Sender<Result<(), sqlx::Error>>,
+    },
+}
+
+const CACHE_CAPACITY: usize = 1024;
+
+/// A handle to the slug-cache actor task: a single owner of an in-memory LRU
+/// cache sitting in front of `shortened_url`, so a hot redirect doesn't need
+/// a SQLite round trip every time. Cheap to `Clone`, since cloning only
+/// duplicates the `mpsc::Sender`.
+#[derive(Clone)]
+struct SlugService {
+    sender: mpsc::Sender<CacheMessage>,
+}
+
+impl SlugService {
+    fn spawn(pool: SqlitePool) -> SlugService {
+        let (sender, mut receiver) = mpsc::channel(32);
+        tokio::spawn(async move {
+            let mut cache: LruCache<String, (String, i64)> =
+                LruCache::new(NonZeroUsize::new(CACHE_CAPACITY).unwrap());
+            let mut conn = pool
+                .acquire()
+                .await
+                .expect("failed to acquire a database connection for the slug cache");
+            while let Some(message) = receiver.recv().await {
+                match message {
+                    CacheMessage::Get(slug, reply) => {
+                        let value = match cache.get(&slug) {
+                            Some(value) => Ok(Some(value.clone())),
+                            None => match id_to_query_params(&slug, &mut conn).await {
+                                Ok(Some(value)) => {
+                                    cache.put(slug, value.clone());
+                                    Ok(Some(value))
+                                }
+                                Ok(None) => Ok(None),
+                                Err(err) => Err(err),
+                            },
+                        };
+                        // The receiver may have dropped (e.g. the request
+                        // was cancelled); nothing to do if so.
+                        let _ = reply.send(value);
+                    }
+                    CacheMessage::Put {
+                        short_url,
+                        long_url,
+                        redirect_code,
+                        reply,
+                    } => {
+                        let result = sqlx::query("INSERT INTO shortened_url VALUES (?, ?, ?)")
+                            .bind(&short_url)
+                            .bind(&long_url)
+                            .bind(redirect_code)
+                            .execute(&mut conn)
+                            .await;
+                        if result.is_ok() {
+                            cache.put(short_url, (long_url, redirect_code));
+                        }
+                        let _ = reply.send(result.map(|_| ()));
+                    }
+                }
+            }
+        });
+        SlugService { sender }
+    }
+
+    async fn get(&self, slug: String) -> Result<Option<(String, i64)>, Error> {
+        let (reply, response) = oneshot::channel();
+        // The actor task only ever exits if it panics, in which case the
+        // backing store is effectively unreachable anyway.
+        if self.sender.send(CacheMessage::Get(slug, reply)).await.is_err() {
+            return Err(Error::Connection(sqlx::Error::PoolClosed));
+        }
+        response.await.map_err(|_| Error::Connection(sqlx::Error::PoolClosed))?
+    }
+
+    // Writes a new row through to SQLite and, only once that succeeds,
+    // updates the cache -- so every write is serialized through this single
+    // task and the database stays authoritative.
+    async fn put(
+        &self,
+        short_url: String,
+        long_url: String,
+        redirect_code: i64,
+    ) -> Result<(), sqlx::Error> {
+        let (reply, response) = oneshot::channel();
+        if self
+            .sender
+            .send(CacheMessage::Put {
+                short_url,
+                long_url,
+                redirect_code,
+                reply,
+            })
+            .await
+            .is_err()
+        {
+            return Err(sqlx::Error::PoolClosed);
+        }
+        response.await.map_err(|_| sqlx::Error::PoolClosed)?
+    }
+}
+
+fn with_service(
+    service: SlugService,
+) -> impl Filter<Extract = (SlugService,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || service.clone())
+}
+
+// Compares two byte strings without short-circuiting on the first mismatch,
+// so the time taken doesn't leak how many leading bytes of a guessed token
+// were correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Requires a `Bearer <BROKEN_FIELD_AUTH_KEY>` Authorization header, rejecting
+// with `Unauthorized` otherwise. Extracts nothing on success, so this is
+// meant to be `.and()`ed in front of the filters it guards.
+fn with_auth(auth_key: Arc<str>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth_key = Arc::clone(&auth_key);
+            async move {
+                let provided = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+                match provided {
+                    Some(token) if constant_time_eq(token.as_bytes(), auth_key.as_bytes()) => {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(Error::Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+// Maps an `Error` to the status code and message it should be served with.
+// `Connection`/`Query` wrap a raw `sqlx::Error`, which can include table and
+// column names (or worse); clients only ever get a fixed generic message for
+// those, while `handle_rejection` still logs the full error (its `Debug`
+// form) for debugging. Every other variant's message is safe to hand back as-is.
+fn error_response(error: &Error) -> (StatusCode, String) {
+    match error {
+        Error::Connection(_) | Error::Query(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "an internal error occurred".to_string(),
+        ),
+        Error::InvalidSlug(_)
+        | Error::InvalidRedirectCode(_)
+        | Error::InvalidBody(_)
+        | Error::InvalidProgram(_) => (StatusCode::BAD_REQUEST, error.to_string()),
+        Error::SlugTaken(_) => (StatusCode::CONFLICT, error.to_string()),
+        Error::Unauthorized => (StatusCode::UNAUTHORIZED, error.to_string()),
+        Error::NotFound => (StatusCode::NOT_FOUND, error.to_string()),
+        Error::MethodNotAllowed => (StatusCode::METHOD_NOT_ALLOWED, error.to_string()),
+    }
+}
+
 // This function receives a `Rejection` and tries to return a custom
-// value, otherwise simply passes the rejection along.
+// value, otherwise simply passes the rejection along. Every branch here
+// but the final fallback funnels through `Error`/`error_response`, so warp's own native
+// rejections (not-found, wrong method, bad body) end up with the same
+// JSON shape as the ones our handlers construct directly.
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    println!("{:?}", err);
-    let code;
-    let message;
-
-    if err.is_not_found() {
-        code = StatusCode::NOT_FOUND;
-        message = "The shortened URL could not be found.";
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
-        // We can handle a specific error, here METHOD_NOT_ALLOWED,
-        // and render it however we want
-        code = StatusCode::METHOD_NOT_ALLOWED;
-        message = "The method is not allowed.";
+    eprintln!("{:?}", err);
+
+    let (status, message) = if err.is_not_found() {
+        error_response(&Error::NotFound)
+    } else if let Some(error) = err.find::<Error>() {
+        error_response(error)
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        error_response(&Error::MethodNotAllowed)
+    } else if let Some(error) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        error_response(&Error::InvalidBody(error.to_string()))
     } else {
         // We should have expected this... Just log and say its a 500
         eprintln!("unhandled rejection: {:?}", err);
-        code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = "Some other error occured.";
-    }
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Some other error occurred.".to_string(),
+        )
+    };
 
-    Ok(warp::reply::with_status(message, code))
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ErrorBody { error: message }),
+        status,
+    ))
 }